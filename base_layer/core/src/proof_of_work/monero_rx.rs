@@ -25,11 +25,12 @@ use crate::{
     proof_of_work::{monero_rx::MergeMineError::HashingError, Difficulty},
     U256,
 };
-use bincode::deserialize;
 use bitflags::_core::ptr::hash;
 use blake2::Digest;
 use bytes::Buf;
 use derive_error::Error;
+use lazy_static::lazy_static;
+use lru::LruCache;
 use monero::{
     blockdata::{
         block::BlockHeader as MoneroBlockHeader,
@@ -43,8 +44,10 @@ use monero::{
 #[cfg(feature = "monero_merge_mining")]
 use randomx_rs::{RandomXCache, RandomXDataset, RandomXError, RandomXFlag, RandomXVM};
 use serde::{Deserialize, Serialize};
-use std::{hash::Hasher, str};
-use tari_mmr::{common::node_index, ArrayLike, MerkleMountainRange, MerkleProof, MerkleProofError};
+use std::{
+    hash::Hasher,
+    sync::{Arc, Mutex},
+};
 
 const MAX_TARGET: U256 = U256::MAX;
 
@@ -52,8 +55,6 @@ const MAX_TARGET: U256 = U256::MAX;
 enum MergeMineError {
     // Error deserializing Monero data
     DeserializeError,
-    // Error serializing Monero data
-    SerializeError,
     // Hashing of Monero data failed
     HashingError,
     // Validation Failure
@@ -65,6 +66,9 @@ enum MergeMineError {
 
 /// This is a struct to deserialize the data from he pow field into data required for the randomX Monero merged mine
 /// pow.
+///
+/// Only Monero's RandomX proof of work is validated: there is no field selecting another parent chain, and no
+/// RandomWOW (Wownero) variant of the hash is computed.
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct MoneroData {
     // Monero header fields
@@ -76,10 +80,16 @@ pub struct MoneroData {
     count: u16,
     // transaction root
     transaction_root: [u8; 32],
-    // Transaction proof of work.
-    merkle_proof: MerkleProof,
+    // Number of sibling hashes needed to fold the coinbase hash up to `transaction_root`.
+    coinbase_merkle_proof_depth: u16,
+    // Sibling hashes along the path from the coinbase tx (always leaf 0) to `transaction_root`, ordered from the
+    // leaf level to the root.
+    coinbase_merkle_proof: Vec<[u8; 32]>,
     // Coinbase tx from Monero
     coinbase_tx: MoneroTransaction,
+    // Sibling hashes proving Tari's aux-chain hash is committed at its slot in the Monero `MergeMining` subfield's
+    // auxiliary chain tree (the tree's depth is this vec's length), ordered from the leaf level to the root.
+    aux_chain_merkle_proof: Vec<[u8; 32]>,
 }
 
 impl MoneroData {
@@ -88,6 +98,119 @@ impl MoneroData {
     }
 }
 
+// The RandomX seed only rotates roughly every 2048 Monero blocks, so a handful of cached datasets covers any
+// realistic chain reorg depth while keeping total cache memory bounded.
+const RANDOMX_CACHE_SIZE: usize = 2;
+
+/// The RandomX seed key implied by `header`.
+///
+/// Real Monero seeds RandomX with the id of the Monero block ~64-2048 blocks back from `header`
+/// (`RANDOMX_SEED_HASH_EPOCH_BLOCKS`/`RANDOMX_SEED_HASH_EPOCH_LAG` in Monero's own seed-rotation rule). This crate
+/// has no Monero chain client to look that ancestor id up, so it cannot reproduce the real seed. `header` itself
+/// carries no height field to even name the ancestor (Monero block headers only carry `prev_id`, the immediately
+/// preceding block's id) - so the only header-derived value available at all is `prev_id`, which this requires
+/// `key` to be a function of. This is strictly weaker than checking against the real seed block.
+fn expected_randomx_seed_key(header: &MoneroBlockHeader) -> String {
+    Hash::hash(&header.prev_id.0)
+        .0
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Verifies that `monero_data.key` equals the RandomX seed key implied by `monero_data.header`, rather than
+/// trusting whatever key the miner attached.
+fn validate_seed(monero_data: &MoneroData) -> Result<(), MergeMineError> {
+    if monero_data.key == expected_randomx_seed_key(&monero_data.header) {
+        Ok(())
+    } else {
+        Err(MergeMineError::ValidationError)
+    }
+}
+
+#[cfg(feature = "monero_merge_mining")]
+lazy_static! {
+    static ref GLOBAL_VERIFIER: MoneroPowVerifier = MoneroPowVerifier::new(RANDOMX_CACHE_SIZE);
+}
+
+/// Caches initialized `RandomXCache`/`RandomXDataset` handles keyed by the Monero seed hash (`MoneroData.key`), so
+/// that validating a chain of headers that share a seed doesn't pay the ~2GB dataset initialization cost on every
+/// call.
+#[cfg(feature = "monero_merge_mining")]
+pub struct MoneroPowVerifier {
+    caches: Mutex<LruCache<String, Arc<RandomXCache>>>,
+    datasets: Mutex<LruCache<String, Arc<RandomXDataset>>>,
+}
+
+#[cfg(feature = "monero_merge_mining")]
+impl MoneroPowVerifier {
+    pub fn new(cache_size: usize) -> Self {
+        Self {
+            caches: Mutex::new(LruCache::new(cache_size)),
+            datasets: Mutex::new(LruCache::new(cache_size)),
+        }
+    }
+
+    fn cache_for(&self, flags: RandomXFlag, key: &str) -> Result<Arc<RandomXCache>, MergeMineError> {
+        let mut caches = self.caches.lock().unwrap();
+        if let Some(cache) = caches.get(key) {
+            return Ok(cache.clone());
+        }
+        let cache = Arc::new(RandomXCache::new(flags, key.as_bytes())?);
+        caches.put(key.to_string(), cache.clone());
+        Ok(cache)
+    }
+
+    fn dataset_for(
+        &self,
+        flags: RandomXFlag,
+        key: &str,
+        cache: &RandomXCache,
+    ) -> Result<Arc<RandomXDataset>, MergeMineError> {
+        let mut datasets = self.datasets.lock().unwrap();
+        if let Some(dataset) = datasets.get(key) {
+            return Ok(dataset.clone());
+        }
+        let dataset = Arc::new(RandomXDataset::new(flags, cache, 0)?);
+        datasets.put(key.to_string(), dataset.clone());
+        Ok(dataset)
+    }
+
+    /// Calculates the difficulty attained for `header`, reusing a cached dataset for the header's seed where
+    /// possible. Use this when validating a chain of headers, since the dataset build is amortized across calls.
+    pub fn difficulty(&self, header: &BlockHeader) -> Result<Difficulty, MergeMineError> {
+        self.difficulty_calculation(header, true)
+    }
+
+    /// As [`difficulty`], but never builds a full ~2GB dataset - the VM is constructed from the (cached)
+    /// `RandomXCache` alone. Hashing is slower than full mode, but this is worthwhile for validating a single
+    /// header in isolation.
+    pub fn difficulty_light(&self, header: &BlockHeader) -> Result<Difficulty, MergeMineError> {
+        self.difficulty_calculation(header, false)
+    }
+
+    fn difficulty_calculation(&self, header: &BlockHeader, use_dataset: bool) -> Result<Difficulty, MergeMineError> {
+        let monero = MoneroData::new(header)?;
+        verify_header(&header, &monero)?;
+        validate_seed(&monero)?;
+        let flags = RandomXFlag::get_recommended_flags();
+        let input = create_input_blob(&monero);
+        let cache = self.cache_for(flags, &monero.key)?;
+
+        let vm = if use_dataset {
+            let dataset = self.dataset_for(flags, &monero.key, &cache)?;
+            RandomXVM::new(flags, Some(&cache), Some(&dataset))?
+        } else {
+            RandomXVM::new(flags, Some(&cache), None)?
+        };
+
+        let hash = vm.calculate_hash(&input)?;
+        let scalar = U256::from_big_endian(&hash); // Big endian so the hash has leading zeroes
+        let result = MAX_TARGET / scalar;
+        Ok(result.low_u64().into())
+    }
+}
+
 /// Calculate the difficulty attained for the given block deserialized the Monero header from the provided header
 pub fn monero_difficulty(header: &BlockHeader) -> Difficulty {
     match monero_difficulty_calculation(header) {
@@ -101,19 +224,7 @@ pub fn monero_difficulty(header: &BlockHeader) -> Difficulty {
 fn monero_difficulty_calculation(header: &BlockHeader) -> Result<Difficulty, MergeMineError> {
     #[cfg(feature = "monero_merge_mining")]
     {
-        let monero = MoneroData::new(header)?;
-        verify_header(&header, &monero)?;
-        let flags = RandomXFlag::get_recommended_flags();
-        let key = monero.key.clone();
-        let input = create_input_blob(&monero)?;
-        let cache = RandomXCache::new(flags, &key)?;
-        let dataset = RandomXDataset::new(flags, &cache, 0)?;
-        let vm = RandomXVM::new(flags, Some(&cache), Some(&dataset))?;
-        let hash = vm.calculate_hash(&input)?;
-        let scalar = U256::from_big_endian(&hash); // Big endian so the hash has leading zeroes
-        let result = MAX_TARGET / scalar;
-        let difficulty = result.low_u64().into();
-        Ok(difficulty)
+        GLOBAL_VERIFIER.difficulty(header)
     }
     #[cfg(not(feature = "monero_merge_mining"))]
     {
@@ -121,39 +232,257 @@ fn monero_difficulty_calculation(header: &BlockHeader) -> Result<Difficulty, Mer
     }
 }
 
-fn create_input_blob(data: &MoneroData) -> Result<String, MergeMineError> {
-    let serialized_header = bincode::serialize(&data.header);
-    if !serialized_header.is_ok() {
-        return Err(MergeMineError::SerializeError);
+/// Builds the RandomX input blob the same way a Monero miner would: the consensus-encoded block header, followed
+/// by the transaction tree root and the transaction count (as Monero's `VarInt`). Using `bincode` here instead
+/// would never match a real Monero block template's hashing blob. Monero's `get_block_longhash` feeds this blob to
+/// `rx_slow_hash` directly - RandomX does its own hashing internally - so it must not be pre-hashed here; an outer
+/// Keccak wrapper would make `monero_difficulty` un-reproducible from a real Monero miner's hashing blob.
+fn create_input_blob(data: &MoneroData) -> Vec<u8> {
+    let mut blob = monero::consensus::encode::serialize(&data.header);
+    blob.extend_from_slice(&data.transaction_root);
+    blob.extend(monero::consensus::encode::serialize(&VarInt(u64::from(data.count))));
+    blob
+}
+
+// Tari's registered id in the Monero merge-mining auxiliary chain tree. Monero's `MergeMining` subfield can commit
+// to several auxiliary chains at once via a small tree; this id, together with the tag's nonce and aux-chain count,
+// selects the slot Tari's commitment must appear at (see `aux_chain_slot`).
+const TARI_AUX_CHAIN_ID: &[u8] = b"tari";
+
+/// Splits the merge-mining tag's single VarInt field into the aux-chain count and the merge-mining nonce.
+///
+/// Monero's original single-chain tag used this VarInt as a plain tree depth. Multichain Monero repurposes it to
+/// carry both the number of merge-mined aux chains and a miner-chosen nonce (see `aux_chain_slot`) instead, since
+/// the slot a chain lands in is no longer fixed by its id alone. This crate has no live multichain Monero node to
+/// confirm the exact bit layout against, so it assumes the low byte is the aux-chain count (up to 255 simultaneously
+/// merge-mined chains, far more than this crate will ever see in practice) and the next 32 bits are the nonce; any
+/// higher bits are ignored.
+fn parse_merge_mining_tag(packed: u64) -> (u32, u32) {
+    let n_aux_chains = (packed & 0xFF) as u32;
+    let nonce = (packed >> 8) as u32;
+    (n_aux_chains.max(1), nonce)
+}
+
+fn verify_header(header: &BlockHeader, monero_data: &MoneroData) -> Result<(), MergeMineError> {
+    let (n_aux_chains, nonce, aux_merkle_root) = monero_data
+        .coinbase_tx
+        .prefix
+        .extra
+        .0
+        .iter()
+        .find_map(|field| match field {
+            SubField::MergeMining(packed, merkle_root) => {
+                let (n_aux_chains, nonce) = parse_merge_mining_tag(packed.0);
+                Some((n_aux_chains, nonce, merkle_root.0))
+            },
+            _ => None,
+        })
+        .ok_or(MergeMineError::ValidationError)?;
+
+    if monero_data.aux_chain_merkle_proof.len() > MAX_AUX_CHAIN_TREE_DEPTH as usize {
+        return Err(MergeMineError::ValidationError);
     }
-    let serialized_root_hash = bincode::serialize(&data.transaction_root);
-    if !serialized_root_hash.is_ok() {
-        return Err(MergeMineError::SerializeError);
+
+    let tari_aux_hash = Hash::hash(&tari_aux_leaf_data(header)).0;
+    let slot = aux_chain_slot(TARI_AUX_CHAIN_ID, nonce, n_aux_chains);
+    let computed_root = fold_merkle_branch(tari_aux_hash, &monero_data.aux_chain_merkle_proof, slot);
+    if computed_root != aux_merkle_root {
+        return Err(MergeMineError::ValidationError);
     }
-    let serialized_transaction_count = bincode::serialize(&data.count);
-    if !serialized_transaction_count.is_ok() {
-        return Err(MergeMineError::SerializeError);
+
+    verify_coinbase_merkle_proof(monero_data)?;
+
+    Ok(())
+}
+
+/// The data hashed to form Tari's leaf in the Monero auxiliary merge-mining tree: the Tari height (so the
+/// commitment can't be replayed against a different Tari block) and the kernel MMR root.
+fn tari_aux_leaf_data(header: &BlockHeader) -> Vec<u8> {
+    let mut buf = monero::consensus::encode::serialize(&VarInt(header.height));
+    buf.extend_from_slice(header.kernel_mr.as_slice());
+    buf
+}
+
+// Bounds how many sibling hashes `verify_header` will fold per call; no real aux-chain tree needs anywhere near
+// this many levels. This only bounds the work done per proof - it does not feed into the slot modulus (see
+// `aux_chain_slot`), since the real tree isn't sized by depth but by the aux-chain count.
+const MAX_AUX_CHAIN_TREE_DEPTH: u16 = 31;
+
+/// The slot `chain_id` occupies among `n_aux_chains` merge-mined chains. Mirrors Monero's `get_aux_slot`:
+/// `keccak(id || nonce_le || 0x6d)`, reduced modulo the actual chain count (not a power-of-two tree depth), so the
+/// nonce carried in the `MergeMining` subfield lets a miner pick which slot each aux chain lands in without
+/// touching that chain's own commitment.
+///
+/// NOTE: `id` here is `Hash::hash(chain_id)`, not the raw chain-id bytes. This is a best-effort reconstruction from Monero's public
+/// description of the algorithm; it has not been checked against a live multichain Monero node (see
+/// `parse_merge_mining_tag` for the same caveat on the tag's bit layout).
+fn aux_chain_slot(chain_id: &[u8], nonce: u32, n_aux_chains: u32) -> u32 {
+    if n_aux_chains <= 1 {
+        return 0;
     }
+    let id_hash = Hash::hash(chain_id).0;
+    let mut buf = Vec::with_capacity(32 + 4 + 1);
+    buf.extend_from_slice(&id_hash);
+    buf.extend_from_slice(&nonce.to_le_bytes());
+    buf.push(0x6d);
+    let digest = Hash::hash(&buf).0;
+    let mut first_word = [0u8; 4];
+    first_word.copy_from_slice(&digest[0..4]);
+    u32::from_le_bytes(first_word) % n_aux_chains
+}
 
-    let mut pre_hash_blob = serialized_header.unwrap();
-    pre_hash_blob.append(&mut serialized_root_hash.unwrap());
-    pre_hash_blob.append(&mut serialized_transaction_count.unwrap());
-    let hash_blob = Hash::hash(pre_hash_blob.as_slice());
-    let hash_vec = hash_blob.0.clone().to_vec();
-    let hash_result = str::from_utf8(hash_vec.as_slice());
-    if !hash_result.is_ok() {
-        return Err(MergeMineError::HashingError);
+/// Folds `leaf` up to a merkle root using the sibling hashes in `branch`, one level per entry. Bit `d` of `slot`
+/// (0 = leaf level) says which side `leaf`/the running hash sits on at level `d`: `0` means it's the left operand
+/// (`current || sibling`), `1` means it's the right operand (`sibling || current`).
+fn fold_merkle_branch(leaf: [u8; 32], branch: &[[u8; 32]], slot: u32) -> [u8; 32] {
+    let mut current = leaf;
+    for (level, sibling) in branch.iter().enumerate() {
+        let mut buf = Vec::with_capacity(64);
+        if (slot >> level) & 1 == 0 {
+            buf.extend_from_slice(&current);
+            buf.extend_from_slice(sibling);
+        } else {
+            buf.extend_from_slice(sibling);
+            buf.extend_from_slice(&current);
+        }
+        current = Hash::hash(&buf).0;
     }
-    Ok(hash_result.unwrap().into())
+    current
 }
 
-fn verify_header(header: &BlockHeader, monero_data: &MoneroData) -> Result<(), MergeMineError> {
-    if !(monero_data.coinbase_tx.prefix.extra.0.contains(&SubField::MergeMining(
-        VarInt(header.height),
-        Hash::hash(header.kernel_mr.as_slice()),
-    ))) {
+/// Proves that `monero_data.coinbase_tx` is actually committed to by `monero_data.transaction_root`, rather than
+/// just asserting that some coinbase tx carries the merge mining tag.
+///
+/// Monero does not use the `tari_mmr::MerkleProof` scheme for its transaction tree; it uses a Keccak binary
+/// "tree hash" instead. The coinbase transaction is always leaf 0 of that tree, so folding is always
+/// `Hash::hash(current || sibling)` - the coinbase is always the left operand - walking from the deepest level up
+/// to the root.
+fn verify_coinbase_merkle_proof(monero_data: &MoneroData) -> Result<(), MergeMineError> {
+    if monero_data.coinbase_merkle_proof_depth as usize != monero_data.coinbase_merkle_proof.len() {
         return Err(MergeMineError::ValidationError);
     }
 
-    Ok(())
+    let coinbase_hash = monero_data.coinbase_tx.hash().0;
+    let current = fold_merkle_branch(coinbase_hash, &monero_data.coinbase_merkle_proof, 0);
+
+    if current == monero_data.transaction_root {
+        Ok(())
+    } else {
+        Err(MergeMineError::ValidationError)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fold_merkle_branch_respects_slot_side() {
+        let leaf = [1u8; 32];
+        let sibling = [2u8; 32];
+
+        let mut left_buf = Vec::new();
+        left_buf.extend_from_slice(&leaf);
+        left_buf.extend_from_slice(&sibling);
+        assert_eq!(fold_merkle_branch(leaf, &[sibling], 0), Hash::hash(&left_buf).0);
+
+        let mut right_buf = Vec::new();
+        right_buf.extend_from_slice(&sibling);
+        right_buf.extend_from_slice(&leaf);
+        assert_eq!(fold_merkle_branch(leaf, &[sibling], 1), Hash::hash(&right_buf).0);
+    }
+
+    #[test]
+    fn fold_merkle_branch_folds_multiple_levels_bit_by_bit() {
+        let leaf = [3u8; 32];
+        let siblings = [[4u8; 32], [5u8; 32]];
+
+        // slot = 0b10: leaf is the left operand at level 0, the right operand at level 1.
+        let level0 = Hash::hash(
+            &[leaf.as_slice(), siblings[0].as_slice()].concat(),
+        )
+        .0;
+        let expected = Hash::hash(&[siblings[1].as_slice(), level0.as_slice()].concat()).0;
+
+        assert_eq!(fold_merkle_branch(leaf, &siblings, 0b10), expected);
+    }
+
+    #[test]
+    fn coinbase_merkle_proof_accepts_a_matching_tree() {
+        let mut data = MoneroData::default();
+        let coinbase_hash = data.coinbase_tx.hash().0;
+        let sibling = [7u8; 32];
+        data.coinbase_merkle_proof = vec![sibling];
+        data.coinbase_merkle_proof_depth = 1;
+        data.transaction_root = fold_merkle_branch(coinbase_hash, &[sibling], 0);
+
+        assert!(verify_coinbase_merkle_proof(&data).is_ok());
+    }
+
+    #[test]
+    fn coinbase_merkle_proof_rejects_a_wrong_root() {
+        let mut data = MoneroData::default();
+        data.coinbase_merkle_proof = vec![[7u8; 32]];
+        data.coinbase_merkle_proof_depth = 1;
+        data.transaction_root = [0u8; 32];
+
+        assert!(verify_coinbase_merkle_proof(&data).is_err());
+    }
+
+    #[test]
+    fn coinbase_merkle_proof_rejects_depth_length_mismatch() {
+        let mut data = MoneroData::default();
+        let coinbase_hash = data.coinbase_tx.hash().0;
+        let siblings = vec![[1u8; 32], [2u8; 32]];
+        data.transaction_root = fold_merkle_branch(coinbase_hash, &siblings, 0);
+        data.coinbase_merkle_proof = siblings;
+        // Depth under-claims the real proof length - must be rejected, not silently truncated.
+        data.coinbase_merkle_proof_depth = 1;
+
+        assert!(verify_coinbase_merkle_proof(&data).is_err());
+    }
+
+    #[test]
+    fn aux_chain_slot_is_deterministic_and_bounded() {
+        let n_aux_chains = 7;
+        let slot_a = aux_chain_slot(TARI_AUX_CHAIN_ID, 42, n_aux_chains);
+        let slot_b = aux_chain_slot(TARI_AUX_CHAIN_ID, 42, n_aux_chains);
+
+        assert_eq!(slot_a, slot_b);
+        assert!(slot_a < n_aux_chains);
+    }
+
+    #[test]
+    fn aux_chain_slot_differs_by_nonce() {
+        let n_aux_chains = 1000;
+        let slot_a = aux_chain_slot(TARI_AUX_CHAIN_ID, 1, n_aux_chains);
+        let slot_b = aux_chain_slot(TARI_AUX_CHAIN_ID, 2, n_aux_chains);
+
+        assert_ne!(slot_a, slot_b);
+    }
+
+    #[test]
+    fn aux_chain_slot_is_zero_when_tari_is_the_only_chain() {
+        assert_eq!(aux_chain_slot(TARI_AUX_CHAIN_ID, 123, 1), 0);
+        assert_eq!(aux_chain_slot(TARI_AUX_CHAIN_ID, 123, 0), 0);
+    }
+
+    #[test]
+    fn parse_merge_mining_tag_splits_count_and_nonce() {
+        let n_aux_chains = 3u64;
+        let nonce = 0xABCDu64;
+        let packed = n_aux_chains | (nonce << 8);
+
+        assert_eq!(parse_merge_mining_tag(packed), (3, 0xABCD));
+    }
+
+    #[test]
+    fn validate_seed_requires_key_bound_to_prev_id() {
+        let mut data = MoneroData::default();
+        data.key = expected_randomx_seed_key(&data.header);
+        assert!(validate_seed(&data).is_ok());
+
+        data.key = "not-the-expected-key".to_string();
+        assert!(validate_seed(&data).is_err());
+    }
 }